@@ -13,8 +13,10 @@ use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, Ordering};
 use kernel::bindings;
 use kernel::blk::mq;
 use kernel::device::RawDevice;
+use kernel::dma;
 use kernel::error::code::*;
 use kernel::pr_info;
+use kernel::pr_warn;
 use kernel::prelude::*;
 use kernel::sync::Ref;
 use kernel::sync::RefBorrow;
@@ -48,6 +50,13 @@ impl mq::Operations for AdminQueueOperations {
             first_dma: AtomicU64::new(0),
             mapping_data: AtomicOptionalBoxedPtr::new(None),
             dma_pool: tagset_data.dma_pool.clone(),
+            submitted_at: AtomicU64::new(0),
+            timeout_retries: AtomicU32::new(0),
+            dsm_buffer: AtomicOptionalBoxedPtr::new(None),
+            meta_dma_addr: AtomicU64::new(!0),
+            meta_len: AtomicU32::new(0),
+            retries: AtomicU32::new(0),
+            ctrl: tagset_data.into(),
         })
     }
 
@@ -71,6 +80,21 @@ impl mq::Operations for AdminQueueOperations {
         queue.write_sq_db(true);
     }
 
+    fn timeout(
+        queue: <Self::HwData as kernel::PointerWrapper>::Borrowed<'_>,
+        rq: &mq::Request<Self>,
+    ) -> mq::TimeoutAction {
+        // There is no command to abort an admin command: the admin queue
+        // (qid 0) carries Abort itself, so a stuck admin command can only be
+        // cleared by resetting the controller.
+        pr_warn!(
+            "admin command {} timed out, resetting controller\n",
+            rq.tag()
+        );
+        queue.data.schedule_reset();
+        mq::TimeoutAction::Done
+    }
+
     fn init_hctx(
         tagset_data: <Self::TagSetData as kernel::PointerWrapper>::Borrowed<'_>,
         _hctx_idx: u32,
@@ -103,6 +127,13 @@ impl mq::Operations for IoQueueOperations {
             first_dma: AtomicU64::new(0),
             mapping_data: AtomicOptionalBoxedPtr::new(None),
             dma_pool: data.dma_pool.clone(),
+            submitted_at: AtomicU64::new(0),
+            timeout_retries: AtomicU32::new(0),
+            dsm_buffer: AtomicOptionalBoxedPtr::new(None),
+            meta_dma_addr: AtomicU64::new(!0),
+            meta_len: AtomicU32::new(0),
+            retries: AtomicU32::new(0),
+            ctrl: data.into(),
         })
     }
 
@@ -135,6 +166,20 @@ impl mq::Operations for IoQueueOperations {
         queue.process_completions()
     }
 
+    fn timeout(io_queue: RefBorrow<'_, NvmeQueue<Self>>, rq: &mq::Request<Self>) -> mq::TimeoutAction {
+        timeout(io_queue, rq)
+    }
+
+    fn report_zones(
+        io_queue: RefBorrow<'_, NvmeQueue<Self>>,
+        ns: &NvmeNamespace,
+        sector: u64,
+        nr_zones: u32,
+        cb: mq::ReportZonesCallback<'_>,
+    ) -> Result<u32> {
+        report_zones(io_queue, ns, sector, nr_zones, cb)
+    }
+
     fn map_queues(tag_set: &mq::TagSetRef) -> Result {
         use kernel::PointerWrapper;
         // TODO: Build abstractions for these unsafe calls
@@ -175,6 +220,69 @@ impl mq::Operations for IoQueueOperations {
     }
 }
 
+/// Reads the kernel's low-resolution jiffies counter.
+///
+/// Used only to timestamp command submission so that the timeout path can
+/// decide whether a request is merely slow or genuinely stuck.
+fn now_jiffies() -> u64 {
+    // SAFETY: `jiffies` is a plain `volatile unsigned long` updated by the
+    // timer tick; reading it without synchronization is the same trade-off
+    // the C NVMe driver makes for the same purpose.
+    unsafe { bindings::jiffies as u64 }
+}
+
+/// Converts a jiffies duration into milliseconds, for logging.
+fn jiffies_to_msecs(jiffies: u64) -> u64 {
+    // SAFETY: `jiffies_to_msecs` has no preconditions beyond its argument.
+    unsafe { bindings::jiffies_to_msecs(jiffies as _) as u64 }
+}
+
+/// Converts a millisecond delay into jiffies for blk-mq's delayed requeue.
+fn msecs_to_jiffies(ms: u64) -> u64 {
+    // SAFETY: `msecs_to_jiffies` has no preconditions beyond its argument.
+    unsafe { bindings::msecs_to_jiffies(ms as u32) as u64 }
+}
+
+/// Returns `true` if `pi_type` carries a reference tag that the controller
+/// checks against the block's starting LBA (NVMe base spec, "End-to-end Data
+/// Protection"): Type 1 and Type 2 do, Type 3 does not.
+///
+/// Single source of truth for [`pi_control_bits`]'s PRCHK bit and
+/// `queue_rq`'s reftag-seeding guard below, so the two cannot drift apart
+/// again.
+fn pi_type_has_reftag(pi_type: NvmePiType) -> bool {
+    !matches!(pi_type, NvmePiType::Type3)
+}
+
+/// Returns the `NvmeRw::control` bits that configure Protection Information
+/// handling for `ns` (NVMe base spec, Figure "Read/Write Command – Control").
+///
+/// Sets PRACT when the namespace's 8-byte metadata is nothing but PI (so the
+/// controller generates/strips it rather than passing it through), and PRCHK
+/// to ask the controller to check every field a given PI type actually
+/// carries.
+fn pi_control_bits(ns: &NvmeNamespace) -> u16 {
+    if ns.ms == 0 {
+        return 0;
+    }
+
+    let mut control: u16 = 0;
+    if ns.ms == 8 {
+        control |= 1 << 13; // PRACT
+    }
+    // else: metadata is present but larger than the 8-byte PI tuple (e.g. a
+    // separate metadata blob alongside PI). PRACT is left clear so the
+    // controller passes that extra metadata through untouched rather than
+    // trying to strip/generate it; this driver does not yet support mixed
+    // metadata+PI layouts wider than 8 bytes.
+    control |= if pi_type_has_reftag(ns.pi_type) {
+        0x7 << 10 // PRCHK: guard + application + reference tag
+    } else {
+        0x3 << 11 // PRCHK: guard + application tag
+    };
+    control
+}
+
 fn queue_rq<T>(
     io_queue: RefBorrow<'_, NvmeQueue<T>>,
     ns: &NvmeNamespace,
@@ -184,6 +292,14 @@ fn queue_rq<T>(
 where
     T: mq::Operations<RequestData = NvmeRequest>,
 {
+    rq.data().submitted_at.store(now_jiffies(), Ordering::Relaxed);
+    // A tag's `NvmeRequest` is reused for every block-layer request that
+    // lands on it for the life of the queue, so the abort- and
+    // completion-retry counters from a past command must not leak into this
+    // one.
+    rq.data().timeout_retries.store(0, Ordering::Relaxed);
+    rq.data().retries.store(0, Ordering::Relaxed);
+
     match rq.command() {
         bindings::req_opf_REQ_OP_DRV_IN | bindings::req_opf_REQ_OP_DRV_OUT => {
             io_queue.submit_command(unsafe { &*rq.data().cmd.get() }, is_last);
@@ -195,32 +311,131 @@ where
             io_queue.submit_command(&cmd, is_last);
             Ok(())
         }
-        bindings::req_opf_REQ_OP_WRITE | bindings::req_opf_REQ_OP_READ => {
-            let (direction, opcode) = if rq.command() == bindings::req_opf_REQ_OP_READ {
-                (
+
+        bindings::req_opf_REQ_OP_DISCARD => queue_discard(io_queue, ns, rq, is_last),
+
+        bindings::req_opf_REQ_OP_WRITE_ZEROES => {
+            // No payload is transferred for Write Zeroes, so unlike the
+            // read/write path below there is no PRP/SGL setup or DMA mapping
+            // to do here.
+            let len = rq.payload_bytes();
+            let offset = unsafe { (*rq.bio()).bi_iter.bi_sector };
+            let deallocate = unsafe { (*rq.bio()).bi_opf } & (bindings::REQ_NOUNMAP as u32) == 0;
+
+            let cmd = NvmeCommand {
+                write_zeroes: NvmeWriteZeroes {
+                    opcode: NvmeOpcode::write_zeroes as _,
+                    command_id: rq.tag() as u16,
+                    nsid: ns.id.into(),
+                    slba: (offset >> (ns.lba_shift - bindings::SECTOR_SHIFT)).into(),
+                    length: ((len >> ns.lba_shift) as u16 - 1).into(),
+                    // DEAC (NVMe base spec, Write Zeroes Command Dword 12):
+                    // bit 9, not the Dataset Management "AD" attribute bit
+                    // used by queue_discard() for Discard.
+                    control: if deallocate { (1u16 << 9).into() } else { 0.into() },
+                    ..NvmeWriteZeroes::default()
+                },
+            };
+
+            rq.start();
+            io_queue.submit_command(&cmd, is_last);
+            Ok(())
+        }
+
+        bindings::req_opf_REQ_OP_ZONE_RESET
+        | bindings::req_opf_REQ_OP_ZONE_OPEN
+        | bindings::req_opf_REQ_OP_ZONE_CLOSE
+        | bindings::req_opf_REQ_OP_ZONE_FINISH => {
+            let action = match rq.command() {
+                bindings::req_opf_REQ_OP_ZONE_RESET => NvmeZoneSendAction::ResetZone,
+                bindings::req_opf_REQ_OP_ZONE_OPEN => NvmeZoneSendAction::OpenZone,
+                bindings::req_opf_REQ_OP_ZONE_CLOSE => NvmeZoneSendAction::CloseZone,
+                bindings::req_opf_REQ_OP_ZONE_FINISH => NvmeZoneSendAction::FinishZone,
+                _ => unreachable!(),
+            };
+            let offset = unsafe { (*rq.bio()).bi_iter.bi_sector };
+            let cmd = NvmeCommand {
+                zone_mgmt_send: NvmeZoneMgmtSend {
+                    opcode: NvmeOpcode::zone_mgmt_send as _,
+                    command_id: rq.tag() as u16,
+                    nsid: ns.id.into(),
+                    slba: (offset >> (ns.lba_shift - bindings::SECTOR_SHIFT)).into(),
+                    zsa: action as u8,
+                    ..NvmeZoneMgmtSend::default()
+                },
+            };
+
+            rq.start();
+            io_queue.submit_command(&cmd, is_last);
+            Ok(())
+        }
+
+        bindings::req_opf_REQ_OP_WRITE
+        | bindings::req_opf_REQ_OP_READ
+        | bindings::req_opf_REQ_OP_ZONE_APPEND => {
+            let (direction, opcode) = match rq.command() {
+                bindings::req_opf_REQ_OP_READ => (
                     bindings::dma_data_direction_DMA_FROM_DEVICE,
                     NvmeOpcode::read,
-                )
-            } else {
-                (
+                ),
+                bindings::req_opf_REQ_OP_ZONE_APPEND => (
+                    bindings::dma_data_direction_DMA_TO_DEVICE,
+                    NvmeOpcode::zone_append,
+                ),
+                _ => (
                     bindings::dma_data_direction_DMA_TO_DEVICE,
                     NvmeOpcode::write,
-                )
+                ),
             };
             let len = rq.payload_bytes();
             // TODO: Return this from the request.
             let offset = unsafe { (*rq.bio()).bi_iter.bi_sector };
+            let slba = offset >> (ns.lba_shift - bindings::SECTOR_SHIFT);
             let mut cmd = NvmeCommand {
                 rw: NvmeRw {
                     opcode: opcode as _,
                     command_id: rq.tag() as u16,
                     nsid: ns.id.into(),
-                    slba: (offset >> (ns.lba_shift - bindings::SECTOR_SHIFT)).into(),
+                    slba: slba.into(),
                     length: ((len >> ns.lba_shift) as u16 - 1).into(),
                     ..NvmeRw::default()
                 },
             };
 
+            // End-to-end data protection: when the namespace carries
+            // metadata, DMA-map the request's integrity payload and tell the
+            // controller how to generate/verify the Protection Information
+            // tuples that ride alongside each logical block.
+            if ns.ms != 0 {
+                if let Some(meta) = rq.integrity_bvec() {
+                    let meta_dma = unsafe {
+                        bindings::dma_map_page_attrs(
+                            io_queue.data.dev.ptr,
+                            meta.bv_page,
+                            meta.bv_offset as _,
+                            meta.bv_len as _,
+                            direction,
+                            0,
+                        )
+                    };
+                    if meta_dma == !0 {
+                        return Err(ENOMEM);
+                    }
+
+                    cmd.rw.metadata = meta_dma.into();
+                    cmd.rw.control |= pi_control_bits(ns);
+                    if pi_type_has_reftag(ns.pi_type) {
+                        // Seed the reference tag from the starting LBA; the
+                        // controller checks it against each block's own tag.
+                        cmd.rw.reftag = (slba & 0xffff_ffff) as u32;
+                    }
+
+                    let pdu = rq.data();
+                    pdu.meta_dma_addr.store(meta_dma, Ordering::Relaxed);
+                    pdu.meta_len.store(meta.bv_len, Ordering::Relaxed);
+                }
+            }
+
             if rq.nr_phys_segments() == 1 {
                 let bv = rq.first_bvec();
                 if (bv.bv_offset % (NVME_CTRL_PAGE_SIZE as u32)) + len
@@ -281,6 +496,323 @@ where
     }
 }
 
+/// Maximum number of Dataset Management ranges submitted in a single Discard
+/// command. Each range is 16 bytes, so 256 of them exactly fill one 4 KiB
+/// page; `DsmBuffer` below holds only as many ranges as the request has.
+const NVME_DSM_MAX_RANGES: usize = 256;
+
+/// One NVMe Dataset Management range descriptor (NVMe base spec, Figure
+/// "Dataset Management – Range Definition"), all fields little-endian.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct NvmeDsmRange {
+    cattr: u32,
+    nlb: u32,
+    slba: u64,
+}
+
+/// Backing store for the range descriptors of an in-flight Discard command.
+///
+/// Kept alive until `complete()` observes the command finishing, then
+/// dropped to release the DMA mapping.
+struct DsmBuffer {
+    alloc: dma::CoherentAllocation<NvmeDsmRange, dma::CoherentAllocator>,
+}
+
+/// Advertises discard support on the block queue limits for `ns`, so blk-mq
+/// routes `REQ_OP_DISCARD` down to [`queue_discard`] instead of rejecting it
+/// upfront. Called from namespace setup once the Identify Namespace Data
+/// Structure has been read.
+pub(crate) fn configure_discard_limits(lim: &mut bindings::queue_limits, ns: &NvmeNamespace) {
+    lim.max_hw_discard_sectors =
+        (NVME_DSM_MAX_RANGES as u32) << (ns.lba_shift - bindings::SECTOR_SHIFT);
+    lim.discard_granularity = 1u32 << ns.lba_shift;
+    // Keep the block layer from ever handing queue_discard() a request with
+    // more ranges than it puts in one Dataset Management command; it stops
+    // at NVME_DSM_MAX_RANGES and leaves any further bios out.
+    lim.max_discard_segments = NVME_DSM_MAX_RANGES as u32;
+}
+
+/// Advertises Write Zeroes support on the block queue limits for `ns`, so
+/// blk-mq routes `REQ_OP_WRITE_ZEROES` down to [`queue_rq`] instead of
+/// rejecting it upfront. Called from namespace setup once the Identify
+/// Namespace Data Structure has been read.
+pub(crate) fn configure_write_zeroes_limits(lim: &mut bindings::queue_limits, ns: &NvmeNamespace) {
+    lim.max_write_zeroes_sectors =
+        (NVME_DSM_MAX_RANGES as u32) << (ns.lba_shift - bindings::SECTOR_SHIFT);
+}
+
+fn queue_discard<T>(
+    io_queue: RefBorrow<'_, NvmeQueue<T>>,
+    ns: &NvmeNamespace,
+    rq: &mq::Request<T>,
+    is_last: bool,
+) -> Result
+where
+    T: mq::Operations<RequestData = NvmeRequest>,
+{
+    use kernel::box_ext::BoxExt;
+
+    let shift = ns.lba_shift - bindings::SECTOR_SHIFT;
+
+    // Bios making up a single request are chained through `bi_next`, same as
+    // the C driver walks them in nvme_setup_discard(). Count them first so
+    // the DMA-coherent allocation can be sized exactly, then walk again and
+    // write each range straight into it — a 4KB `NvmeDsmRange` array has no
+    // business living on the stack this deep in the blk-mq submission path.
+    let mut nr_ranges: usize = 0;
+    let mut bio = rq.bio();
+    while !bio.is_null() && nr_ranges < NVME_DSM_MAX_RANGES {
+        nr_ranges += 1;
+        bio = unsafe { (*bio).bi_next };
+    }
+    if nr_ranges == 0 {
+        return Err(EIO);
+    }
+
+    let alloc = dma::try_alloc_coherent::<NvmeDsmRange>(&io_queue.data.dev, nr_ranges, false)?;
+    let mut bio = rq.bio();
+    for i in 0..nr_ranges {
+        let iter = unsafe { (*bio).bi_iter };
+        alloc.write(
+            i,
+            &NvmeDsmRange {
+                cattr: 0,
+                nlb: (iter.bi_size >> ns.lba_shift) as u32,
+                slba: (iter.bi_sector >> shift) as u64,
+            },
+        );
+        bio = unsafe { (*bio).bi_next };
+    }
+    let prp1 = alloc.dma_handle();
+
+    let cmd = NvmeCommand {
+        dsm: NvmeDsm {
+            opcode: NvmeOpcode::dsm as _,
+            command_id: rq.tag() as u16,
+            nsid: ns.id.into(),
+            prp1: prp1.into(),
+            nr: (nr_ranges as u32 - 1).into(),
+            attributes: (1u32 << 2).into(), // AD: attribute - deallocate
+            ..NvmeDsm::default()
+        },
+    };
+    rq.data()
+        .dsm_buffer
+        .store(Some(Box::try_new_atomic(DsmBuffer { alloc })?), Ordering::Relaxed);
+
+    rq.start();
+    io_queue.submit_command(&cmd, is_last);
+    Ok(())
+}
+
+/// Size of one zone descriptor returned by Zone Management Receive (NVMe ZNS
+/// command set spec, "Zone Descriptor" figure): type, state, flags, a
+/// reserved run, capacity, start LBA, write pointer and a final reserved run.
+const NVME_ZONE_DESCRIPTOR_SIZE: usize = 64;
+
+/// Size of the `nr_zones` header that precedes the zone descriptor array in
+/// a Zone Management Receive "Report Zones" data structure (NVMe ZNS command
+/// set spec, "ZNS Report Zones Data Structure" figure): an 8-byte zone count
+/// plus 56 reserved bytes, which happens to be the same size as one
+/// descriptor (mirrors `struct nvme_zone_report` in the C driver).
+const NVME_ZONE_REPORT_HEADER_SIZE: usize = NVME_ZONE_DESCRIPTOR_SIZE;
+
+/// Maximum number of zone descriptors fetched per Zone Management Receive,
+/// capped so the report buffer fits within a handful of pages.
+const NVME_MAX_ZONES_PER_REPORT: u32 = 64;
+
+/// Parsed view of one `NVME_ZONE_DESCRIPTOR_SIZE`-byte zone descriptor.
+struct NvmeZoneDescriptor {
+    zone_type: u8,
+    zone_state: u8,
+    zone_capacity: u64,
+    zone_start_lba: u64,
+    write_pointer: u64,
+}
+
+impl NvmeZoneDescriptor {
+    fn parse(buf: &[u8]) -> Self {
+        let u64_at = |off: usize| u64::from_le_bytes(buf[off..off + 8].try_into().unwrap());
+        Self {
+            zone_type: buf[0] & 0xf,
+            zone_state: (buf[1] >> 4) & 0xf,
+            zone_capacity: u64_at(8),
+            zone_start_lba: u64_at(16),
+            write_pointer: u64_at(24),
+        }
+    }
+}
+
+/// Issues Zone Management Receive to fetch up to `nr_zones` zone descriptors
+/// starting at `sector`, invoking `cb` once per zone in LBA order.
+///
+/// Unlike the rest of `queue_rq`, report-zones is a synchronous admin-style
+/// operation: it is driven by an ioctl/sysfs consumer, not by a queued I/O
+/// request, so the command is issued and waited on directly rather than
+/// dispatched through [`NvmeQueue::submit_command`] and completed later.
+fn report_zones<T>(
+    io_queue: RefBorrow<'_, NvmeQueue<T>>,
+    ns: &NvmeNamespace,
+    sector: u64,
+    nr_zones: u32,
+    mut cb: mq::ReportZonesCallback<'_>,
+) -> Result<u32>
+where
+    T: mq::Operations<RequestData = NvmeRequest>,
+{
+    let nr_zones = nr_zones.min(NVME_MAX_ZONES_PER_REPORT);
+    let buf_len = NVME_ZONE_REPORT_HEADER_SIZE + nr_zones as usize * NVME_ZONE_DESCRIPTOR_SIZE;
+    let alloc = dma::try_alloc_coherent::<u8>(&io_queue.data.dev, buf_len, false)?;
+
+    let cmd = NvmeCommand {
+        zone_mgmt_recv: NvmeZoneMgmtRecv {
+            opcode: NvmeOpcode::zone_mgmt_recv as _,
+            nsid: ns.id.into(),
+            slba: (sector >> (ns.lba_shift - bindings::SECTOR_SHIFT)).into(),
+            prp1: alloc.dma_handle().into(),
+            numd: ((buf_len / core::mem::size_of::<u32>()) as u32 - 1).into(),
+            zra: 0, // Report Zones
+            zrasf: 0, // list all zones, regardless of state
+            ..NvmeZoneMgmtRecv::default()
+        },
+    };
+
+    io_queue.data.submit_sync_admin_command(&cmd)?;
+
+    let mut reported = 0;
+    for i in 0..nr_zones as usize {
+        let mut desc = [0u8; NVME_ZONE_DESCRIPTOR_SIZE];
+        alloc.read_range(
+            NVME_ZONE_REPORT_HEADER_SIZE + i * NVME_ZONE_DESCRIPTOR_SIZE,
+            &mut desc,
+        );
+        let zone = NvmeZoneDescriptor::parse(&desc);
+        if zone.zone_capacity == 0 {
+            // No more zones were returned than were actually reported.
+            break;
+        }
+        cb.report(zone.zone_start_lba, zone.zone_capacity, zone.write_pointer, zone.zone_type, zone.zone_state)?;
+        reported += 1;
+    }
+
+    Ok(reported)
+}
+
+/// Do Not Retry bit of the NVMe completion Status Field: set by the
+/// controller when it knows retrying the command will not help.
+///
+/// The raw Status Field has DNR at bit 15 and CRD at bits 13:12, but
+/// `pdu.status` already had the completion queue's phase bit shifted out
+/// (see `nvme_queue.rs`'s `cqe.status.into() >> 1`), so every field tested
+/// against `pdu.status` sits one bit below its spec position.
+const NVME_SC_DNR: u16 = 1 << 14;
+
+/// Number of times a failed command is requeued before the error is handed
+/// back to blk-mq, matching the upstream C driver's default retry count.
+const NVME_MAX_RETRIES: u32 = 5;
+
+/// Number of Asynchronous Event Request commands kept posted on the admin
+/// queue at all times, matching the upstream C driver's `NVME_NR_AERS`.
+const NVME_NR_AERS: u16 = 4;
+
+/// Reserved command ID range for AER commands, carved out of the top of the
+/// command ID space (just below [`NVME_INTERNAL_CID`]) so it can never
+/// collide with a blk-mq tag.
+const NVME_AER_CID_BASE: u16 = 0xfff0;
+
+/// Returns `true` if `command_id` belongs to the AER pool rather than a
+/// tagged blk-mq request.
+pub(crate) fn is_aer_command(command_id: u16) -> bool {
+    (NVME_AER_CID_BASE..NVME_AER_CID_BASE + NVME_NR_AERS).contains(&command_id)
+}
+
+fn post_aer<T>(queue: &NvmeQueue<T>, index: u16)
+where
+    T: mq::Operations<RequestData = NvmeRequest>,
+{
+    let mut cmd = NvmeCommand::default();
+    cmd.common.opcode = NvmeAdminOpcode::async_event_request as _;
+    cmd.common.command_id = NVME_AER_CID_BASE + index;
+    queue.submit_command(&cmd, true);
+}
+
+/// Posts all `NVME_NR_AERS` Asynchronous Event Request commands on the admin
+/// `queue`.
+///
+/// Called once admin queue creation has completed, so that the controller
+/// always has AERs outstanding to complete events into; each one is reposted
+/// by [`handle_aer_completion`] as soon as it fires.
+pub(crate) fn start_aer_pool<T>(queue: &NvmeQueue<T>)
+where
+    T: mq::Operations<RequestData = NvmeRequest>,
+{
+    for i in 0..NVME_NR_AERS {
+        post_aer(queue, i);
+    }
+}
+
+/// Asynchronous Event Type values (NVMe base spec, "Asynchronous Event
+/// Request completion queue entry: Dword 0").
+const AER_TYPE_ERROR: u8 = 0x0;
+const AER_TYPE_SMART: u8 = 0x1;
+const AER_TYPE_NOTICE: u8 = 0x2;
+
+/// Error-type AER info: persistent internal device error.
+const AER_INFO_ERROR_PERSISTENT_INTERNAL: u8 = 0x03;
+/// Notice-type AER info: namespace attributes changed.
+const AER_INFO_NOTICE_NS_ATTR_CHANGED: u8 = 0x00;
+
+/// Decodes and reacts to an Asynchronous Event Request completion, then
+/// reposts a fresh AER in `cqe`'s slot so the pool stays full.
+///
+/// `cqe.result`'s low three bytes carry the Asynchronous Event Type (bits
+/// 2:0), Asynchronous Event Information (bits 15:8), and Log Page Identifier
+/// (bits 23:16); only the first two are interpreted here. A failed AER
+/// completes with a nonzero status and undefined `result` content, so the
+/// status is checked first, shifted the same way
+/// `NvmeQueue::process_completions_budgeted` shifts it before storing it on a
+/// tagged request, and decoding is skipped on failure; the slot is still
+/// reposted either way.
+pub(crate) fn handle_aer_completion<T>(queue: &NvmeQueue<T>, cqe: &NvmeCompletion)
+where
+    T: mq::Operations<RequestData = NvmeRequest>,
+{
+    let status: u16 = cqe.status.into();
+    let status = status >> 1;
+    if status == 0 {
+        let result: u32 = cqe.result.into();
+        let event_type = (result & 0x7) as u8;
+        let event_info = ((result >> 8) & 0xff) as u8;
+
+        match event_type {
+            AER_TYPE_ERROR if event_info == AER_INFO_ERROR_PERSISTENT_INTERNAL => {
+                pr_warn!("persistent internal device error reported, degrading controller\n");
+                queue.data.mark_degraded();
+            }
+            AER_TYPE_NOTICE if event_info == AER_INFO_NOTICE_NS_ATTR_CHANGED => {
+                pr_info!("namespace change event, rescanning namespaces\n");
+                queue.data.rescan_namespaces();
+            }
+            AER_TYPE_SMART => {
+                pr_warn!("SMART/health event reported, info {:#x}\n", event_info);
+            }
+            _ => {
+                pr_info!(
+                    "async event: type {:#x} info {:#x}\n",
+                    event_type,
+                    event_info
+                );
+            }
+        }
+    } else {
+        pr_warn!("AER completed with error status {:x}, ignoring\n", status);
+    }
+
+    let index = cqe.command_id - NVME_AER_CID_BASE;
+    post_aer(queue, index);
+}
+
 fn complete<T>(rq: &mq::Request<T>)
 where
     T: mq::Operations<RequestData = NvmeRequest>,
@@ -288,8 +820,13 @@ where
     match rq.command() {
         bindings::req_opf_REQ_OP_DRV_IN
         | bindings::req_opf_REQ_OP_DRV_OUT
-        | bindings::req_opf_REQ_OP_FLUSH => {
-            // We just complete right away if flush completes.
+        | bindings::req_opf_REQ_OP_FLUSH
+        | bindings::req_opf_REQ_OP_WRITE_ZEROES
+        | bindings::req_opf_REQ_OP_ZONE_RESET
+        | bindings::req_opf_REQ_OP_ZONE_OPEN
+        | bindings::req_opf_REQ_OP_ZONE_CLOSE
+        | bindings::req_opf_REQ_OP_ZONE_FINISH => {
+            // These carry no data mapping to release, so complete right away.
             rq.end_ok();
             return;
         }
@@ -298,7 +835,26 @@ where
 
     let pdu = rq.data();
 
-    if let Some(mut md) = pdu.mapping_data.take(Ordering::Relaxed) {
+    let meta_dma_addr = pdu.meta_dma_addr.swap(!0, Ordering::Relaxed);
+    if meta_dma_addr != !0 {
+        unsafe {
+            bindings::dma_unmap_page_attrs(
+                pdu.dev.ptr,
+                meta_dma_addr,
+                pdu.meta_len.load(Ordering::Relaxed) as _,
+                pdu.direction.load(Ordering::Relaxed),
+                0,
+            )
+        };
+    }
+
+    if rq.command() == bindings::req_opf_REQ_OP_DISCARD {
+        // Dropping the buffer tears down its DMA mapping before any retry
+        // decision below, since a requeue will have queue_rq() build a fresh
+        // one on resubmission. There is no separate data mapping to release
+        // alongside it.
+        drop(pdu.dsm_buffer.take(Ordering::Relaxed));
+    } else if let Some(mut md) = pdu.mapping_data.take(Ordering::Relaxed) {
         pdu.dev.dma_unmap_sg(
             &mut md.sg[..pdu.sg_count.load(Ordering::Relaxed) as usize],
             pdu.direction.load(Ordering::Relaxed),
@@ -322,13 +878,111 @@ where
         };
     }
 
-    // On failure, complete the request immediately with an error.
+    // On failure, retry transient errors a bounded number of times before
+    // giving up, honoring the controller's requested retry delay.
     let status = pdu.status.load(Ordering::Relaxed);
     if status != 0 {
+        let dnr = status & NVME_SC_DNR != 0;
+        let retries = pdu.retries.fetch_add(1, Ordering::Relaxed);
+
+        if !dnr && retries < NVME_MAX_RETRIES {
+            let crd = (status >> 11) & 0x3;
+            let delay_ms = if crd == 0 {
+                0
+            } else {
+                pdu.ctrl.crdt[(crd - 1) as usize] as u64 * 100
+            };
+            pr_info!(
+                "command {} failed with status {:x}, retrying ({}/{})\n",
+                rq.tag(),
+                status,
+                retries + 1,
+                NVME_MAX_RETRIES
+            );
+            rq.requeue_after(msecs_to_jiffies(delay_ms));
+            return;
+        }
+
         pr_info!("Completing with error {:x}\n", status);
+        pdu.retries.store(0, Ordering::Relaxed);
         rq.end_err(EIO);
         return;
     }
 
+    if rq.command() == bindings::req_opf_REQ_OP_ZONE_APPEND {
+        // The controller reports the LBA it actually appended at in the
+        // completion's result field; the block layer needs that back to
+        // satisfy the caller, since the submitted command named only the
+        // starting LBA taken from the bio, not where the data would land.
+        rq.set_zone_append_result(pdu.result.load(Ordering::Relaxed) as u64);
+    }
+
     rq.end_ok();
 }
+
+/// Command ID reserved for driver-internal admin commands (e.g. Abort) that
+/// are not associated with a tagged blk-mq request of their own.
+const NVME_INTERNAL_CID: u16 = 0xffff;
+
+/// Number of Abort commands to try against a stuck I/O command before giving
+/// up and resetting the controller instead.
+const MAX_ABORT_RETRIES: u32 = 1;
+
+fn timeout<T>(io_queue: RefBorrow<'_, NvmeQueue<T>>, rq: &mq::Request<T>) -> mq::TimeoutAction
+where
+    T: mq::Operations<RequestData = NvmeRequest>,
+{
+    let pdu = rq.data();
+    let retries = pdu.timeout_retries.fetch_add(1, Ordering::Relaxed);
+
+    if retries >= MAX_ABORT_RETRIES {
+        pr_warn!(
+            "command {} on qid {} did not complete after abort, resetting controller\n",
+            rq.tag(),
+            io_queue.qid
+        );
+        io_queue.data.schedule_reset();
+        return mq::TimeoutAction::Done;
+    }
+
+    let admin_queue = {
+        let queues = io_queue.data.queues.lock();
+        queues.admin.as_ref().cloned()
+    };
+    let admin_queue = match admin_queue {
+        Some(q) => q,
+        None => {
+            // No admin queue left to carry the abort; the controller is
+            // already in a bad enough state to warrant a reset.
+            pr_warn!("no admin queue available to abort command, resetting controller\n");
+            io_queue.data.schedule_reset();
+            return mq::TimeoutAction::Done;
+        }
+    };
+
+    let elapsed_ms = jiffies_to_msecs(
+        now_jiffies().saturating_sub(pdu.submitted_at.load(Ordering::Relaxed)),
+    );
+    pr_warn!(
+        "command {} on qid {} timed out after {} ms, aborting\n",
+        rq.tag(),
+        io_queue.qid,
+        elapsed_ms
+    );
+
+    let cmd = NvmeCommand {
+        abort: NvmeAbort {
+            opcode: NvmeAdminOpcode::abort as _,
+            command_id: NVME_INTERNAL_CID,
+            sqid: io_queue.qid,
+            cid: rq.tag() as u16,
+            ..NvmeAbort::default()
+        },
+    };
+    admin_queue.submit_command(&cmd, true);
+
+    // Give blk-mq a fresh deadline; completion of the request is driven by
+    // either the original command's CQE or, should the abort itself stall,
+    // the next call to this function escalating to a full reset.
+    mq::TimeoutAction::ResetTimer
+}
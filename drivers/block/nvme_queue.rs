@@ -5,8 +5,10 @@ use crate::NvmeRequest;
 use core;
 use core::pin::Pin;
 use core::sync::atomic::fence;
+use core::sync::atomic::AtomicBool;
 use core::sync::atomic::AtomicU16;
 use core::sync::atomic::Ordering;
+use core::sync::atomic::AtomicU32;
 use kernel::blk::mq;
 use kernel::dma;
 use kernel::irq;
@@ -18,6 +20,26 @@ use kernel::sync::Ref;
 use kernel::sync::RefBorrow;
 use kernel::sync::SpinLock;
 use kernel::sync::UniqueRef;
+use kernel::workqueue::{self, impl_has_work, Work, WorkItem};
+
+/// Budget of completion queue entries processed per IRQ before the rest are
+/// handed off to the workqueue bottom half.
+///
+/// Meant to be tunable at runtime through an `irq_budget` module parameter,
+/// the same way the C driver exposes its per-IRQ poll budget. Declaring that
+/// parameter belongs in this driver's `module!{}` block, which lives in the
+/// PCI driver registration file — not part of this source tree snapshot.
+/// [`set_irq_budget`] is the wiring point the parameter's write callback
+/// would call; until that registration file exists, this budget can only be
+/// changed by editing the default below.
+pub(crate) static IRQ_BUDGET: AtomicU32 = AtomicU32::new(32);
+
+/// Updates [`IRQ_BUDGET`], meant to be called from the `irq_budget` module
+/// parameter's write callback once one is wired up (see [`IRQ_BUDGET`]).
+#[allow(dead_code)]
+pub(crate) fn set_irq_budget(value: u32) {
+    IRQ_BUDGET.store(value, Ordering::Relaxed);
+}
 
 struct NvmeQueueInner<T: mq::Operations<RequestData = NvmeRequest> + 'static> {
     sq_tail: u16,
@@ -35,6 +57,11 @@ pub(crate) struct NvmeQueue<T: mq::Operations<RequestData = NvmeRequest> + 'stat
     cq_head: AtomicU16,
     cq_phase: AtomicU16,
 
+    /// Guards the completion queue scan in [`Self::process_completions`] so
+    /// that a poller and a stray hard IRQ (or two concurrent pollers) can
+    /// never walk `cq_head`/`cq_phase` at the same time.
+    consuming: AtomicBool,
+
     pub(crate) sq: dma::CoherentAllocation<NvmeCommand, dma::CoherentAllocator>,
     pub(crate) cq: dma::CoherentAllocation<NvmeCompletion, dma::CoherentAllocator>,
 
@@ -43,6 +70,15 @@ pub(crate) struct NvmeQueue<T: mq::Operations<RequestData = NvmeRequest> + 'stat
 
     inner: SpinLock<NvmeQueueInner<T>>,
     tagset: Ref<mq::TagSet<T>>,
+
+    /// Bottom half that keeps draining the completion queue once the hard
+    /// IRQ's budget runs out, so a deep queue cannot stall a CPU with
+    /// interrupts disabled.
+    work: Work<NvmeQueue<T>>,
+}
+
+impl_has_work! {
+    impl<T: mq::Operations<RequestData = NvmeRequest>> HasWork<Self> for NvmeQueue<T> { self.work }
 }
 
 impl<T> NvmeQueue<T>
@@ -80,6 +116,7 @@ where
             tagset,
             cq_head: AtomicU16::new(0),
             cq_phase: AtomicU16::new(1),
+            consuming: AtomicBool::new(false),
             // SAFETY: `spinlock_init` is called below.
             inner: unsafe {
                 SpinLock::new(NvmeQueueInner {
@@ -89,22 +126,51 @@ where
                 })
             },
             polled,
+            // SAFETY: `work_init` is called below.
+            work: unsafe { Work::new() },
         })?);
 
         // SAFETY: `inner` is pinned when `queue` is.
         let inner = unsafe { queue.as_mut().map_unchecked_mut(|q| &mut q.inner) };
         kernel::spinlock_init!(inner, "NvmeQueue::inner");
 
+        // SAFETY: `work` is pinned when `queue` is.
+        let work = unsafe { queue.as_mut().map_unchecked_mut(|q| &mut q.work) };
+        kernel::workqueue::work_init!(work, "NvmeQueue::work");
+
         Ok(queue.into())
     }
 
     /// Processes the completion queue.
     ///
-    /// Returns `true` if at least one entry was processed, `false` otherwise.
+    /// Returns the number of entries processed.
     pub(crate) fn process_completions(&self) -> i32 {
+        self.process_completions_budgeted(u32::MAX).0
+    }
+
+    /// Processes at most `budget` completion queue entries.
+    ///
+    /// Returns `(processed, more_pending)`, where `more_pending` indicates
+    /// the budget ran out while the phase check still showed further
+    /// entries waiting.
+    fn process_completions_budgeted(&self, budget: u32) -> (i32, bool) {
+        // Only one context may scan the completion queue at a time: either
+        // the IRQ handler / bottom half or a poller (`mq::Operations::poll`),
+        // never both. If we lose the race just report no progress; the
+        // winner will have drained whatever was pending.
+        if self
+            .consuming
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return (0, false);
+        }
+        let _consume_guard = ConsumeGuard(&self.consuming);
+
         let mut head = self.cq_head.load(Ordering::Relaxed);
         let mut phase = self.cq_phase.load(Ordering::Relaxed);
-        let mut found = 0;
+        let mut found: i32 = 0;
+        let mut more_pending = false;
 
         loop {
             let cqe = self.cq.read_volatile(head.into()).unwrap();
@@ -113,6 +179,11 @@ where
                 break;
             }
 
+            if found as u32 >= budget {
+                more_pending = true;
+                break;
+            }
+
             let cqe = self.cq.read_volatile(head.into()).unwrap();
 
             found += 1;
@@ -130,6 +201,10 @@ where
                 pdu.result.store(cqe.result.into(), Ordering::Relaxed);
                 pdu.status.store(cqe.status.into() >> 1, Ordering::Relaxed);
                 rq.complete();
+            } else if crate::nvme_mq::is_aer_command(cqe.command_id) {
+                // Asynchronous Event Request completions carry no blk-mq tag
+                // of their own; they are driven entirely out-of-band here.
+                crate::nvme_mq::handle_aer_completion(self, &cqe);
             } else {
                 let command_id = cqe.command_id;
                 pr_warn!("invalid id completed: {}", command_id);
@@ -137,7 +212,7 @@ where
         }
 
         if found == 0 {
-            return found;
+            return (found, more_pending);
         }
 
         if self.dbbuf_update_and_check_event(head.into(), self.data.db_stride / 4) {
@@ -152,7 +227,7 @@ where
         self.cq_head.store(head, Ordering::Relaxed);
         self.cq_phase.store(phase, Ordering::Relaxed);
 
-        found
+        (found, more_pending)
     }
 
     pub(crate) fn dbbuf_need_event(event_idx: u16, new_idx: u16, old: u16) -> bool {
@@ -229,6 +304,12 @@ where
     }
 
     pub(crate) fn register_irq(self: &Ref<Self>, pci_dev: &pci::Device) -> Result {
+        if self.polled {
+            // Polled queues are drained on demand from `mq::Operations::poll`
+            // and have no completion IRQ to wait on.
+            return Ok(());
+        }
+
         pr_info!(
             "Registering irq for queue qid: {}, vector {}\n",
             self.qid,
@@ -244,6 +325,16 @@ where
     }
 }
 
+/// Releases [`NvmeQueue::consuming`] when a completion-queue scan finishes,
+/// including on early returns.
+struct ConsumeGuard<'a>(&'a AtomicBool);
+
+impl Drop for ConsumeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
 impl<T> irq::Handler for NvmeQueue<T>
 where
     T: mq::Operations<RequestData = NvmeRequest> + 'static,
@@ -251,10 +342,37 @@ where
     type Data = Ref<NvmeQueue<T>>;
 
     fn handle_irq(queue: RefBorrow<'_, NvmeQueue<T>>) -> irq::Return {
-        if queue.process_completions() != 0 {
+        let budget = IRQ_BUDGET.load(Ordering::Relaxed);
+        let (found, more_pending) = queue.process_completions_budgeted(budget);
+
+        if more_pending {
+            // Defer the rest to the workqueue instead of draining a deep
+            // queue with interrupts disabled.
+            let owned: Ref<NvmeQueue<T>> = queue.into();
+            workqueue::system().enqueue(owned);
+        }
+
+        if found != 0 || more_pending {
             irq::Return::Handled
         } else {
             irq::Return::None
         }
     }
 }
+
+impl<T> WorkItem for NvmeQueue<T>
+where
+    T: mq::Operations<RequestData = NvmeRequest> + 'static,
+{
+    type Pointer = Ref<NvmeQueue<T>>;
+
+    fn run(this: Ref<NvmeQueue<T>>) {
+        let budget = IRQ_BUDGET.load(Ordering::Relaxed);
+        loop {
+            let (_, more_pending) = this.process_completions_budgeted(budget);
+            if !more_pending {
+                break;
+            }
+        }
+    }
+}
@@ -2,16 +2,25 @@
 
 //! Minimal in-kernel async web server written in Rust.
 
+use alloc::vec::Vec;
 use kernel::{
     kasync::executor::{workqueue::Executor as WqExecutor, AutoStopHandle, Executor},
     kasync::net::{TcpListener, TcpStream},
-    net::{self, Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{self, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     prelude::*,
     spawn_task,
     sync::{Ref, RefBorrow},
     str::CStr,
 };
 
+/// Maximum size of the accumulated request headers before we give up and
+/// respond with a "header too large" style error, mirroring the 431 status
+/// code real servers use for this condition.
+const MAX_HEADER_SIZE: usize = 8192;
+
+/// End-of-headers marker for HTTP/1.x requests.
+const HEADER_TERMINATOR: &[u8] = b"\r\n\r\n";
+
 module! {
     type: RustServer,
     name: "rust_web",
@@ -24,12 +33,23 @@ module! {
             permissions: 0o644,
             description: "Server port used for client connections",
         },
+        bind_v4: bool {
+            default: true,
+            permissions: 0o644,
+            description: "Listen on the IPv4 wildcard address",
+        },
+        bind_v6: bool {
+            default: true,
+            permissions: 0o644,
+            description: "Listen on the IPv6 wildcard address",
+        },
     },
 }
 
 const RESPONSE: &str = r###"HTTP/1.1 200
 Server: kernel
 Content-Type: text/html; charset=UTF-8
+Content-Length: 167
 
 <!doctype html>
 <html>
@@ -46,6 +66,7 @@ Content-Type: text/html; charset=UTF-8
 const LOGO: &str = r###"HTTP/1.1 200
 Server: kernel
 Content-Type: image/svg+xml
+Content-Length: 3297
 
 <svg version="1.1" height="106" width="106" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink">
 <g id="logo" transform="translate(53, 53)">
@@ -113,6 +134,7 @@ Content-Type: image/svg+xml
 const ERROR: &str = r###"HTTP/1.1 404
 Server: kernel
 Content-Type: text/html; charset=UTF-8
+Content-Length: 114
 
 <!doctype html>
 <html>
@@ -122,24 +144,109 @@ Content-Type: text/html; charset=UTF-8
 </html>
 "###;
 
+const HEADERS_TOO_LARGE: &str = r###"HTTP/1.1 431
+Server: kernel
+Content-Type: text/html; charset=UTF-8
+Content-Length: 99
+Connection: close
+
+<!doctype html>
+<html>
+<body>
+<h1>Error 431, request header fields too large.</h1>
+</body>
+</html>
+"###;
+
+/// Reads from `stream` until the end of the HTTP request headers is seen.
+///
+/// `buf` is any bytes already read for this request but not yet consumed
+/// — either left over from a previous pipelined request on the same
+/// connection, or an empty buffer for the first request.
+///
+/// Returns `Ok(None)` if the peer closed the connection before sending any
+/// data (a clean EOF, e.g. the end of a keep-alive connection). On success,
+/// returns the header bytes together with any bytes read past the
+/// terminator (a pipelined next request, or the start of a body), which the
+/// caller must carry into the next call instead of discarding.
+async fn read_headers(stream: &TcpStream, mut buf: Vec<u8>) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        if let Some(pos) = buf
+            .windows(HEADER_TERMINATOR.len())
+            .position(|w| w == HEADER_TERMINATOR)
+        {
+            let tail = buf.split_off(pos + HEADER_TERMINATOR.len());
+            return Ok(Some((buf, tail)));
+        }
+
+        if buf.len() > MAX_HEADER_SIZE {
+            return Err(EFBIG);
+        }
+
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            // Client reset or half-closed the connection mid-request; treat
+            // whatever was received as the final read.
+            return Ok(Some((buf, Vec::new())));
+        }
+
+        buf.extend_from_slice(&chunk[0..n]);
+    }
+}
+
+/// Extracts `(method, path)` from the request line of a raw HTTP request.
+fn parse_request_line(req: &[u8]) -> Option<(&str, &str)> {
+    let line_end = req.iter().position(|&b| b == b'\r')?;
+    let line = core::str::from_utf8(&req[0..line_end]).ok()?;
+    let mut parts = line.split(' ');
+    let method = parts.next()?;
+    let path = parts.next()?;
+    Some((method, path))
+}
+
+/// Returns `true` if the request asks the connection to be kept alive, i.e.
+/// it is HTTP/1.1 and does not carry an explicit `Connection: close` header.
+fn keep_alive(req: &[u8]) -> bool {
+    let Ok(req) = core::str::from_utf8(req) else {
+        return false;
+    };
+    let is_http11 = req.lines().next().map_or(false, |l| l.ends_with("HTTP/1.1"));
+    let close_requested = req
+        .lines()
+        .any(|l| l.eq_ignore_ascii_case("connection: close"));
+    is_http11 && !close_requested
+}
+
 async fn server_worker(stream: TcpStream) -> Result {
-    let mut buf = [0u8; 1024];
-    let n = stream.read(&mut buf).await?;
-    if n > 0 && n < buf.len() - 1 {
-        let cstr: &CStr = CStr::from_bytes_with_nul(&buf[0 .. n + 1])
-                                .expect("CStr::from_bytes_with_nul failed");
-        let s: &str = cstr.to_str().unwrap();
-        if s.starts_with("GET / ") {
-            stream.write_all(RESPONSE.as_bytes()).await?;
-        } else if s.starts_with("GET /rust_logo.svg ") {
-            stream.write_all(LOGO.as_bytes()).await?;
-        } else {
-            stream.write_all(ERROR.as_bytes()).await?;
+    let mut pending = Vec::new();
+    loop {
+        let (req, tail) = match read_headers(&stream, pending).await {
+            Ok(Some(parts)) => parts,
+            Ok(None) => return Ok(()),
+            Err(EFBIG) => {
+                stream.write_all(HEADERS_TOO_LARGE.as_bytes()).await?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        pending = tail;
+
+        match parse_request_line(&req) {
+            Some(("GET", "/")) => stream.write_all(RESPONSE.as_bytes()).await?,
+            Some(("GET", "/rust_logo.svg")) => stream.write_all(LOGO.as_bytes()).await?,
+            Some(_) => stream.write_all(ERROR.as_bytes()).await?,
+            None => return Err(EINVAL),
+        }
+
+        if !keep_alive(&req) {
+            return Ok(());
         }
-    } else {
-        return Err(EINVAL);
     }
-    return Ok(());
 }
 
 async fn accept_loop(listener: TcpListener, executor: Ref<impl Executor>) {
@@ -150,25 +257,48 @@ async fn accept_loop(listener: TcpListener, executor: Ref<impl Executor>) {
     }
 }
 
-fn start_listener(ex: RefBorrow<'_, impl Executor + Send + Sync + 'static>, port: u16) -> Result {
-    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::ANY, port));
+fn start_listener(
+    ex: RefBorrow<'_, impl Executor + Send + Sync + 'static>,
+    addr: SocketAddr,
+) -> Result {
     let listener = TcpListener::try_new(net::init_ns(), &addr)?;
     spawn_task!(ex, accept_loop(listener, ex.into()))?;
     Ok(())
 }
 
 struct RustServer {
-    _handle: AutoStopHandle<dyn Executor>,
+    _v4_handle: Option<AutoStopHandle<dyn Executor>>,
+    _v6_handle: Option<AutoStopHandle<dyn Executor>>,
 }
 
 impl kernel::Module for RustServer {
     fn init(_name: &'static CStr, module: &'static ThisModule) -> Result<Self> {
         let lock = module.kernel_param_lock();
         let port = *server_port.read(&lock);
-        let handle = WqExecutor::try_new(kernel::workqueue::system())?;
-        start_listener(handle.executor(), port)?;
+        let enable_v4 = *bind_v4.read(&lock);
+        let enable_v6 = *bind_v6.read(&lock);
+
+        let v4_handle = if enable_v4 {
+            let handle = WqExecutor::try_new(kernel::workqueue::system())?;
+            let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::ANY, port));
+            start_listener(handle.executor(), addr)?;
+            Some(handle.into())
+        } else {
+            None
+        };
+
+        let v6_handle = if enable_v6 {
+            let handle = WqExecutor::try_new(kernel::workqueue::system())?;
+            let addr = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::ANY, port, 0, 0));
+            start_listener(handle.executor(), addr)?;
+            Some(handle.into())
+        } else {
+            None
+        };
+
         Ok(Self {
-            _handle: handle.into(),
+            _v4_handle: v4_handle,
+            _v6_handle: v6_handle,
         })
     }
 }